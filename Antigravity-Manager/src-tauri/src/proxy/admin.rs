@@ -0,0 +1,17 @@
+// 只读管理端点:把 usage_tracker 攒的数据暴露成 HTTP 接口,
+// 方便 operator 查看"每个会话真实花了 Gemini 多少 token vs Claude Code 以为花了多少"。
+
+use axum::{routing::get, Json, Router};
+
+use super::mappers::claude::utils::{usage_report, SessionUsageReport};
+
+/// `GET /admin/usage`:返回所有活跃会话的真实 vs 展示用量快照。
+async fn get_usage_report() -> Json<Vec<SessionUsageReport>> {
+    Json(usage_report())
+}
+
+/// 管理端路由,挂载在代理服务的顶层 router 上,例如:
+/// `app_router.nest("/admin", admin::router())`
+pub fn router() -> Router {
+    Router::new().route("/usage", get(get_usage_report))
+}
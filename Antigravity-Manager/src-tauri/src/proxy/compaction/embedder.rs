@@ -0,0 +1,137 @@
+// 文本嵌入后端:要么本地跑 candle BertModel,要么转发给外部嵌入服务。
+//
+// `compact_before_forwarding` 是从 axum 的异步转发路径上调用的,所以这里
+// 两个实现都不能用阻塞 I/O:本地 BERT 的前向传播是纯 CPU 同步计算,用
+// `tokio::task::block_in_place` 跑,避免卡住当前 worker 线程上的其它任务;
+// 远程 embedder 直接用异步版 `reqwest::Client`。
+
+use super::CompactionError;
+use candle_core::{Device, Tensor};
+use candle_transformers::models::bert::BertModel;
+use tokenizers::Tokenizer;
+
+/// 把一段文本编码成用于余弦相似度检索的向量
+#[async_trait::async_trait]
+pub trait Embedder: Send + Sync {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, CompactionError>;
+}
+
+/// 用本地 candle BertModel + HuggingFace `tokenizer.json` 做嵌入,
+/// 不依赖任何外部网络调用,适合离线/自托管部署。
+pub struct BertEmbedder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl BertEmbedder {
+    /// 从本地磁盘上的 HuggingFace 仓库快照加载模型权重与分词器
+    pub fn from_pretrained(model_dir: &std::path::Path) -> Result<Self, CompactionError> {
+        let device = Device::Cpu;
+        let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json"))
+            .map_err(|e| CompactionError::Embedding(format!("failed to load tokenizer: {e}")))?;
+
+        let config_path = model_dir.join("config.json");
+        let config_str = std::fs::read_to_string(&config_path)
+            .map_err(|e| CompactionError::Embedding(format!("failed to read bert config: {e}")))?;
+        let config: candle_transformers::models::bert::Config = serde_json::from_str(&config_str)
+            .map_err(|e| CompactionError::Embedding(format!("failed to parse bert config: {e}")))?;
+
+        let weights_path = model_dir.join("model.safetensors");
+        let vb = unsafe {
+            candle_nn::VarBuilder::from_mmaped_safetensors(&[weights_path], candle_core::DType::F32, &device)
+                .map_err(|e| CompactionError::Embedding(format!("failed to load weights: {e}")))?
+        };
+        let model = BertModel::load(vb, &config)
+            .map_err(|e| CompactionError::Embedding(format!("failed to build bert model: {e}")))?;
+
+        Ok(Self { model, tokenizer, device })
+    }
+}
+
+impl BertEmbedder {
+    /// 实际的同步前向传播,跑在 `block_in_place` 里,不直接暴露成 async fn
+    fn embed_sync(&self, text: &str) -> Result<Vec<f32>, CompactionError> {
+        let encoding = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(|e| CompactionError::Embedding(format!("tokenize failed: {e}")))?;
+
+        let ids = encoding.get_ids();
+        let token_ids = Tensor::new(ids, &self.device)
+            .and_then(|t| t.unsqueeze(0))
+            .map_err(|e| CompactionError::Embedding(format!("tensor build failed: {e}")))?;
+        let token_type_ids = token_ids
+            .zeros_like()
+            .map_err(|e| CompactionError::Embedding(format!("tensor build failed: {e}")))?;
+
+        let hidden = self
+            .model
+            .forward(&token_ids, &token_type_ids, None)
+            .map_err(|e| CompactionError::Embedding(format!("bert forward failed: {e}")))?;
+
+        // 对 token 维做平均池化,得到整句的向量表示
+        let pooled = hidden
+            .mean(1)
+            .map_err(|e| CompactionError::Embedding(format!("pooling failed: {e}")))?
+            .squeeze(0)
+            .map_err(|e| CompactionError::Embedding(format!("pooling failed: {e}")))?;
+
+        pooled
+            .to_vec1::<f32>()
+            .map_err(|e| CompactionError::Embedding(format!("failed to read pooled tensor: {e}")))
+    }
+}
+
+#[async_trait::async_trait]
+impl Embedder for BertEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, CompactionError> {
+        // candle 的 forward pass 是同步、CPU 密集型的;block_in_place 让 tokio
+        // 把当前线程标记为"正在阻塞",调度器会补一个线程跑其它异步任务,
+        // 而不是让整个 worker 线程被纯 CPU 计算占满。
+        tokio::task::block_in_place(|| self.embed_sync(text))
+    }
+}
+
+/// 把嵌入计算外包给一个外部 HTTP 嵌入端点(例如自建的嵌入服务或云厂商 API)
+pub struct RemoteEmbedder {
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+impl RemoteEmbedder {
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Embedder for RemoteEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, CompactionError> {
+        #[derive(serde::Serialize)]
+        struct Request<'a> {
+            input: &'a str,
+        }
+        #[derive(serde::Deserialize)]
+        struct Response {
+            embedding: Vec<f32>,
+        }
+
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .json(&Request { input: text })
+            .send()
+            .await
+            .map_err(|e| CompactionError::Embedding(format!("request to {} failed: {e}", self.endpoint)))?;
+
+        response
+            .json::<Response>()
+            .await
+            .map_err(|e| CompactionError::Embedding(format!("invalid embedding response: {e}")))
+            .map(|r| r.embedding)
+    }
+}
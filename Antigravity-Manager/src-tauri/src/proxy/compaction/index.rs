@@ -0,0 +1,94 @@
+// 按会话 id 分区的向量索引,用来检索"和最新用户消息最相关的历史轮次"。
+//
+// 默认用内嵌的 HNSW 近邻索引,单个会话量级不大,没必要引入外部向量数据库;
+// 但接口留出了切换到 qdrant 之类外部服务的空间。
+
+use super::CompactionError;
+use hnsw_rs::hnsw::Hnsw;
+use hnsw_rs::dist::DistCosine;
+use std::collections::{HashMap, HashSet};
+
+const MAX_NB_CONNECTION: usize = 16;
+const EF_CONSTRUCTION: usize = 200;
+
+struct ConversationShard {
+    hnsw: Hnsw<'static, f32, DistCosine>,
+    indexed_turns: HashSet<usize>,
+}
+
+impl ConversationShard {
+    fn new() -> Self {
+        Self {
+            hnsw: Hnsw::new(MAX_NB_CONNECTION, 10_000, 16, EF_CONSTRUCTION, DistCosine {}),
+            indexed_turns: HashSet::new(),
+        }
+    }
+}
+
+/// 每个会话一个独立的 HNSW 分片,避免跨会话互相检索到无关内容。
+pub struct ConversationIndex {
+    shards: HashMap<String, ConversationShard>,
+}
+
+impl ConversationIndex {
+    pub fn new() -> Self {
+        Self { shards: HashMap::new() }
+    }
+
+    pub fn contains(&self, conversation_id: &str, turn_index: usize) -> bool {
+        self.shards
+            .get(conversation_id)
+            .map(|shard| shard.indexed_turns.contains(&turn_index))
+            .unwrap_or(false)
+    }
+
+    pub fn insert(&mut self, conversation_id: &str, turn_index: usize, vector: Vec<f32>) {
+        let shard = self
+            .shards
+            .entry(conversation_id.to_string())
+            .or_insert_with(ConversationShard::new);
+        shard.hnsw.insert((&vector, turn_index));
+        shard.indexed_turns.insert(turn_index);
+    }
+
+    /// 在给定的候选轮次范围内,返回与 `query_vector` 最相似的最多 `top_k` 个轮次下标,
+    /// 过滤掉相似度低于 `similarity_floor` 的结果。
+    pub fn top_k_similar(
+        &self,
+        conversation_id: &str,
+        query_vector: &[f32],
+        candidates: &[usize],
+        top_k: usize,
+        similarity_floor: f32,
+    ) -> Result<HashSet<usize>, CompactionError> {
+        let Some(shard) = self.shards.get(conversation_id) else {
+            return Ok(HashSet::new());
+        };
+        if candidates.is_empty() || top_k == 0 {
+            return Ok(HashSet::new());
+        }
+
+        // ef_search 取大一些保证召回,候选范围之外的结果在下面按 candidates 过滤掉
+        let ef_search = (top_k * 4).max(EF_CONSTRUCTION);
+        let neighbors = shard.hnsw.search(query_vector, top_k * 4, ef_search);
+
+        let candidate_set: HashSet<usize> = candidates.iter().copied().collect();
+        let mut kept: Vec<(usize, f32)> = neighbors
+            .into_iter()
+            .filter(|n| candidate_set.contains(&n.d_id))
+            .map(|n| (n.d_id, 1.0 - n.distance))
+            .filter(|(_, similarity)| *similarity >= similarity_floor)
+            .collect();
+
+        kept.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        kept.truncate(top_k);
+
+        Ok(kept.into_iter().map(|(turn_index, _)| turn_index).collect())
+    }
+}
+
+impl Default for ConversationIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,295 @@
+// RAG 上下文压缩子系统
+// 在把完整对话转发给 Gemini 之前,先用检索增强的方式裁剪历史,
+// 使 `to_claude_usage` 的数字缩放退化为兜底手段,而不是唯一的"压缩"机制。
+//
+// 实际调用点: `mappers::claude::build_gemini_turns`,在序列化 Gemini
+// `generateContent` 请求体之前调用 [`compact_before_forwarding`]。
+
+mod embedder;
+mod index;
+
+pub use embedder::{BertEmbedder, Embedder, RemoteEmbedder};
+pub use index::ConversationIndex;
+
+use std::fmt;
+use std::sync::RwLock;
+
+/// 一轮对话原始内容,索引和重建提示词都以它为最小单位
+#[derive(Debug, Clone)]
+pub struct ConversationTurn {
+    pub role: String,
+    pub text: String,
+}
+
+/// RAG 压缩子系统的可调参数
+#[derive(Debug, Clone)]
+pub struct CompactionConfig {
+    /// 达到该比例的上下文占用才触发 RAG 压缩
+    pub trigger_fraction: f64,
+    /// 检索返回的历史轮次数量
+    pub top_k: usize,
+    /// 无论相似度如何,始终保留的最近轮次数量
+    pub recency_window: usize,
+    /// 低于该余弦相似度的检索结果被丢弃
+    pub similarity_floor: f32,
+    /// 被丢弃的轮次是否用一行摘要占位,而不是彻底消失
+    pub summarize_dropped: bool,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            trigger_fraction: 0.5,
+            top_k: 8,
+            recency_window: 6,
+            similarity_floor: 0.2,
+            summarize_dropped: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompactionError {
+    Embedding(String),
+    Index(String),
+}
+
+impl fmt::Display for CompactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactionError::Embedding(msg) => write!(f, "embedding failed: {msg}"),
+            CompactionError::Index(msg) => write!(f, "index lookup failed: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for CompactionError {}
+
+/// 判断这次请求是否应该走 RAG 压缩,而不是直接把完整历史转发给 Gemini。
+pub fn should_compact(prompt_token_count: u32, context_limit: u32, config: &CompactionConfig) -> bool {
+    if context_limit == 0 {
+        return false;
+    }
+    (prompt_token_count as f64 / context_limit as f64) >= config.trigger_fraction
+}
+
+/// 用检索到的相关历史轮次 + 最近窗口重建发给 Gemini 的对话,替代整段历史。
+///
+/// `system` 永远保留在最前面;`recency_window` 条最近的轮次永远保留;
+/// 剩下的历史按与最新一条用户消息的余弦相似度取 top_k,其余的轮次
+/// 根据 `summarize_dropped` 决定是整段丢弃还是替换成一行摘要占位符。
+pub async fn rebuild_prompt(
+    conversation_id: &str,
+    system: Option<&ConversationTurn>,
+    history: &[ConversationTurn],
+    latest_user_message: &str,
+    index: &mut ConversationIndex,
+    embedder: &dyn Embedder,
+    config: &CompactionConfig,
+) -> Result<Vec<ConversationTurn>, CompactionError> {
+    // 对话里新出现的轮次先补进索引,保证检索库是最新的
+    for (turn_index, turn) in history.iter().enumerate() {
+        if !index.contains(conversation_id, turn_index) {
+            let vector = embedder.embed(&turn.text).await?;
+            index.insert(conversation_id, turn_index, vector);
+        }
+    }
+
+    let query_vector = embedder.embed(latest_user_message).await?;
+    let recency_start = history.len().saturating_sub(config.recency_window);
+    let retained: std::collections::HashSet<usize> = (recency_start..history.len()).collect();
+
+    let candidates: Vec<usize> = (0..recency_start).collect();
+    let retrieved = index.top_k_similar(conversation_id, &query_vector, &candidates, config.top_k, config.similarity_floor)?;
+
+    let mut rebuilt = Vec::new();
+    if let Some(system_turn) = system {
+        rebuilt.push(system_turn.clone());
+    }
+
+    for turn_index in 0..history.len() {
+        let is_recent = retained.contains(&turn_index);
+        let is_retrieved = retrieved.contains(&turn_index);
+
+        if is_recent || is_retrieved {
+            rebuilt.push(history[turn_index].clone());
+        } else if config.summarize_dropped {
+            rebuilt.push(ConversationTurn {
+                role: history[turn_index].role.clone(),
+                text: format!("[已省略第 {} 轮历史对话,未被判定为相关]", turn_index + 1),
+            });
+        }
+    }
+
+    Ok(rebuilt)
+}
+
+/// RAG 子系统的运行时状态:配置好的 embedder + 按会话的向量索引。
+/// 不配置 runtime 时,`compact_before_forwarding` 直接原样透传完整历史,
+/// 行为退化为"没有这个子系统"。
+///
+/// `embedder`/`index` 包在 `Arc` 里,这样可以在拿到读锁之后立刻把句柄克隆出来、
+/// 释放读锁,再去 `.await` 真正的嵌入计算——不会让一个 std `RwLock` 的读锁
+/// 跨越 await 点(那样会在持锁期间把写者晾在一边,且容易踩 `!Send` 的坑)。
+struct CompactionRuntime {
+    config: CompactionConfig,
+    embedder: std::sync::Arc<dyn Embedder>,
+    index: std::sync::Arc<tokio::sync::Mutex<ConversationIndex>>,
+}
+
+fn runtime() -> &'static RwLock<Option<CompactionRuntime>> {
+    static RUNTIME: std::sync::OnceLock<RwLock<Option<CompactionRuntime>>> = std::sync::OnceLock::new();
+    RUNTIME.get_or_init(|| RwLock::new(None))
+}
+
+/// 配置 RAG 压缩子系统使用的 embedder 与参数。启动时调用一次。
+pub fn configure_runtime(embedder: std::sync::Arc<dyn Embedder>, config: CompactionConfig) {
+    *runtime().write().unwrap() = Some(CompactionRuntime {
+        config,
+        embedder,
+        index: std::sync::Arc::new(tokio::sync::Mutex::new(ConversationIndex::new())),
+    });
+}
+
+/// Gemini 转发路径在序列化请求体之前调用这个函数:如果配置了 RAG 子系统
+/// 并且这次请求的真实 `prompt_token_count` 越过了 `trigger_fraction`,
+/// 就用检索增强后的历史替换完整历史;否则原样转发 `system` + `history`。
+pub async fn compact_before_forwarding(
+    conversation_id: &str,
+    prompt_token_count: u32,
+    context_limit: u32,
+    system: Option<&ConversationTurn>,
+    history: &[ConversationTurn],
+    latest_user_message: &str,
+) -> Vec<ConversationTurn> {
+    let full_history = || {
+        system
+            .cloned()
+            .into_iter()
+            .chain(history.iter().cloned())
+            .collect::<Vec<_>>()
+    };
+
+    // 只在读锁里把需要的句柄克隆出来,然后立刻释放读锁——`std::sync::RwLock`
+    // 的守卫不是 `Send` 友好的,绝不能带着它跨越下面的 `.await` 点。
+    let handles = {
+        let guard = runtime().read().unwrap();
+        guard.as_ref().map(|runtime| {
+            (runtime.config.clone(), runtime.embedder.clone(), runtime.index.clone())
+        })
+    };
+
+    let Some((config, embedder, index)) = handles else {
+        return full_history();
+    };
+
+    if !should_compact(prompt_token_count, context_limit, &config) {
+        return full_history();
+    }
+
+    let mut index = index.lock().await;
+    match rebuild_prompt(
+        conversation_id,
+        system,
+        history,
+        latest_user_message,
+        &mut index,
+        embedder.as_ref(),
+        &config,
+    )
+    .await
+    {
+        Ok(compacted) => compacted,
+        Err(err) => {
+            tracing::warn!("[RAG-Compaction] falling back to full history for conversation={conversation_id}: {err}");
+            full_history()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_compact_respects_trigger_fraction() {
+        let config = CompactionConfig { trigger_fraction: 0.5, ..Default::default() };
+        assert!(!should_compact(400_000, 1_000_000, &config));
+        assert!(should_compact(600_000, 1_000_000, &config));
+    }
+
+    #[test]
+    fn test_should_compact_handles_zero_context_limit() {
+        let config = CompactionConfig::default();
+        assert!(!should_compact(100, 0, &config));
+    }
+
+    #[tokio::test]
+    async fn test_compact_before_forwarding_passes_through_when_unconfigured() {
+        let system = ConversationTurn { role: "system".to_string(), text: "be helpful".to_string() };
+        let history = vec![ConversationTurn { role: "user".to_string(), text: "hi".to_string() }];
+
+        let turns = compact_before_forwarding(
+            "conv-unconfigured",
+            900_000,
+            1_000_000,
+            Some(&system),
+            &history,
+            "latest message",
+        )
+        .await;
+
+        // 没有配置 runtime 时应原样透传 system + 完整历史
+        assert_eq!(turns.len(), 2);
+        assert_eq!(turns[0].text, "be helpful");
+        assert_eq!(turns[1].text, "hi");
+    }
+
+    /// 测试专用的 embedder:不跑真实模型,按文本长度给一个向量,够用来驱动
+    /// 检索控制流(是否真的丢弃了旧轮次),不关心检索质量。
+    struct StubEmbedder;
+
+    #[async_trait::async_trait]
+    impl Embedder for StubEmbedder {
+        async fn embed(&self, text: &str) -> Result<Vec<f32>, CompactionError> {
+            Ok(vec![text.len() as f32, 1.0])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_prompt_actually_drops_non_recent_non_retrieved_turns() {
+        let config = CompactionConfig {
+            trigger_fraction: 0.5,
+            top_k: 0,
+            recency_window: 1,
+            similarity_floor: 0.0,
+            summarize_dropped: false,
+        };
+        let system = ConversationTurn { role: "system".to_string(), text: "be helpful".to_string() };
+        let history: Vec<ConversationTurn> = (0..8)
+            .map(|i| ConversationTurn { role: "user".to_string(), text: format!("turn {i}") })
+            .collect();
+
+        let mut index = ConversationIndex::new();
+        let rebuilt = rebuild_prompt(
+            "conv-rebuild-shrink-test",
+            Some(&system),
+            &history,
+            "latest user message",
+            &mut index,
+            &StubEmbedder,
+            &config,
+        )
+        .await
+        .expect("rebuild should succeed");
+
+        // system + 只保留 recency_window=1 条最近轮次,其余全部丢弃;
+        // 如果这里还是 system + 8 条,说明压缩根本没生效。
+        assert!(
+            rebuilt.len() < history.len() + 1,
+            "expected compaction to shrink history, got {} turns back",
+            rebuilt.len()
+        );
+        assert_eq!(rebuilt.last().unwrap().text, "turn 7");
+    }
+}
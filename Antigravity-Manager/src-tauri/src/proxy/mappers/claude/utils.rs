@@ -1,6 +1,23 @@
 // Claude 辅助函数
 // JSON Schema 清理、签名处理等
 
+// 这四个都是文件子模块,挂在 `utils` 这个文件模块下面,所以物理上必须放在
+// `claude/utils/<name>.rs`(而不是跟 `utils.rs` 平级的 `claude/<name>.rs`)——
+// 放错位置是编译期 `E0583`,曾经在这几个模块上发生过,记在这里防止再犯。
+mod model_registry;
+mod request_builder;
+mod scaling_expression;
+mod usage_tracker;
+
+pub use model_registry::{global_registry, ModelProfile, ModelProfileRule, ModelRegistry};
+pub use request_builder::{build_gemini_request_body, build_gemini_turns};
+pub use scaling_expression::{configure as configure_scaling_expression, ScalingExpressionError};
+pub use usage_tracker::{usage_report, SessionUsageReport};
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
 // 已移除未使用的 Value 导入
 
 /// 将 JSON Schema 中的类型名称转为大写 (Gemini 要求)
@@ -8,78 +25,258 @@
 // 已移除未使用的 uppercase_schema_types 函数
 
 /// 根据模型名称获取上下文 Token 限制
+///
+/// 保留这个函数签名是为了兼容仍然只需要裸 `context_limit` 的调用方;
+/// 内部已经改为走 [`ModelRegistry`],不再是写死的子串匹配。
 pub fn get_context_limit_for_model(model: &str) -> u32 {
-    if model.contains("pro") {
-        2_097_152 // 2M for Pro
-    } else if model.contains("flash") {
-        1_048_576 // 1M for Flash
-    } else {
-        1_048_576 // Default 1M
+    global_registry().resolve(model).context_limit
+}
+
+/// 【自适应反馈控制器】
+///
+/// 不再用写死的分段曲线猜测 Claude Code 何时会触发 compact 提示,
+/// 而是像 Go GC 的 pacer 一样,用一个按模型持久化的系数 `k` 去逼近目标:
+/// Claude Code 在"显示填充率"越过 profile 的 `compact_threshold`(约 0.92)时发起 compact。
+/// 我们观测每个会话下一次请求的 `prompt_token_count` 是否相比上一次骤降,
+/// 骤降即视为一次 compact 事件,此时上一条记录的真实填充率就是 `r_trigger`。
+/// 再根据期望的真实填充率 `r*`(默认 0.90,尽量用满 Gemini 的大窗口)反向调节 `k`,
+/// 使其收敛到 `display ≈ compact_threshold` 恰好发生在 `r ≈ r*` 处。
+mod scaling_controller {
+    use super::*;
+
+    /// k 的调节学习率
+    const ALPHA: f64 = 0.1;
+    /// k 的取值范围,保证 display 不会失控
+    const K_MIN: f64 = 0.2;
+    const K_MAX: f64 = 3.0;
+    /// 初始猜测值,大致对应原先分段曲线在中段的压缩比
+    const K_INITIAL: f64 = 0.6;
+    /// 判定为一次 compact 触发所需的骤降比例(下一次 / 上一次)
+    const DROP_RATIO: f64 = 0.5;
+    /// `r_trigger` 的 EMA 平滑系数:单次观测噪声很大(一次异常的用户消息长度
+    /// 就能让骤降提前/推迟),所以不能直接拿原始样本去更新 `k`。
+    const EMA_BETA: f64 = 0.3;
+
+    /// 代理看不到"会话结束"的信号,`conversation_table` 按 conversation_id
+    /// 建索引,长期运行下去只会无限增长。和 `usage_tracker::sessions()` 一样,
+    /// 用 TTL + 容量上限做淘汰。`k_table` / `ema_r_trigger_table` 按模型建索引,
+    /// 模型数量天然有限,不需要淘汰。
+    const CONVERSATION_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+    const MAX_TRACKED_CONVERSATIONS: usize = 5_000;
+
+    struct ConversationState {
+        /// 上一次请求的真实 prompt_token_count,用于检测骤降
+        last_total_raw: u32,
+        /// 上一次请求计算出的真实填充率,骤降发生时即为 r_trigger
+        last_ratio: f64,
+        /// 上一次观测到这个会话的时间,用于 TTL 淘汰
+        last_seen: Instant,
+    }
+
+    fn k_table() -> &'static Mutex<HashMap<String, f64>> {
+        static TABLE: OnceLock<Mutex<HashMap<String, f64>>> = OnceLock::new();
+        TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+    }
+
+    fn conversation_table() -> &'static Mutex<HashMap<String, ConversationState>> {
+        static TABLE: OnceLock<Mutex<HashMap<String, ConversationState>>> = OnceLock::new();
+        TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+    }
+
+    /// 先按 TTL 清掉太久没再发过请求的会话;清完还是超过容量上限的话,
+    /// 再淘汰最后一次观测时间最早的那一个。
+    fn evict_stale_conversations(conversations: &mut HashMap<String, ConversationState>) {
+        let now = Instant::now();
+        conversations.retain(|_, state| now.duration_since(state.last_seen) < CONVERSATION_TTL);
+
+        if conversations.len() >= MAX_TRACKED_CONVERSATIONS {
+            if let Some(oldest_id) = conversations
+                .iter()
+                .min_by_key(|(_, state)| state.last_seen)
+                .map(|(conversation_id, _)| conversation_id.clone())
+            {
+                conversations.remove(&oldest_id);
+            }
+        }
+    }
+
+    /// 按模型持久化的 `r_trigger` EMA,用来damp掉单次观测的噪声
+    fn ema_r_trigger_table() -> &'static Mutex<HashMap<String, f64>> {
+        static TABLE: OnceLock<Mutex<HashMap<String, f64>>> = OnceLock::new();
+        TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+    }
+
+    /// 用新观测到的 `r_trigger` 更新该模型的 EMA,并返回平滑后的值
+    fn smoothed_r_trigger(model: &str, observed: f64) -> f64 {
+        let mut table = ema_r_trigger_table().lock().unwrap();
+        let smoothed = match table.get(model) {
+            Some(prev_ema) => EMA_BETA * observed + (1.0 - EMA_BETA) * prev_ema,
+            None => observed,
+        };
+        table.insert(model.to_string(), smoothed);
+        smoothed
+    }
+
+    /// 读取(或初始化)某个模型当前的自适应系数 `k`
+    fn current_k(model: &str) -> f64 {
+        let mut table = k_table().lock().unwrap();
+        *table.entry(model.to_string()).or_insert(K_INITIAL)
+    }
+
+    /// 处理一次新请求:检测是否发生了 compact 骤降,若发生则更新 `k`,
+    /// 并把本次的真实用量记录下来供下一次请求比较。
+    ///
+    /// `profile` 的 `scaling_threshold` / `compact_threshold` / `target_true_fill`
+    /// 都来自 [`super::ModelRegistry`] 解析出的结果,不再是写死的常量。
+    fn observe_and_update_k(model: &str, conversation_id: &str, total_raw: u32, profile: &ModelProfile) -> f64 {
+        let mut conversations = conversation_table().lock().unwrap();
+        evict_stale_conversations(&mut conversations);
+        let ratio = total_raw as f64 / profile.context_limit.max(1) as f64;
+
+        if let Some(prev) = conversations.get(conversation_id) {
+            let sharp_drop = prev.last_total_raw > profile.scaling_threshold
+                && (total_raw as f64) < prev.last_total_raw as f64 * DROP_RATIO;
+
+            if sharp_drop && prev.last_ratio > 0.0 {
+                // 单次观测噪声很大,先过一遍按模型的 EMA 再喂给 k 的更新公式
+                let r_trigger = smoothed_r_trigger(model, prev.last_ratio);
+                let mut k_map = k_table().lock().unwrap();
+                let k = k_map.entry(model.to_string()).or_insert(K_INITIAL);
+                let target_term = profile.compact_threshold / profile.target_true_fill;
+                let observed_term = profile.compact_threshold / r_trigger;
+                let updated = *k + ALPHA * (target_term - observed_term);
+                *k = updated.clamp(K_MIN, K_MAX);
+
+                tracing::debug!(
+                    "[Claude-Scaling] compact detected for conversation={} model={} raw_r_trigger={:.3} ema_r_trigger={:.3} -> k={:.3}",
+                    conversation_id, model, prev.last_ratio, r_trigger, *k
+                );
+            }
+        }
+
+        conversations.insert(
+            conversation_id.to_string(),
+            ConversationState {
+                last_total_raw: total_raw,
+                last_ratio: ratio,
+                last_seen: Instant::now(),
+            },
+        );
+
+        current_k(model)
+    }
+
+    /// 根据当前自适应系数计算显示填充率
+    pub fn display_ratio(model: &str, conversation_id: &str, total_raw: u32, profile: &ModelProfile) -> f64 {
+        let k = observe_and_update_k(model, conversation_id, total_raw, profile);
+        let ratio = total_raw as f64 / profile.context_limit.max(1) as f64;
+        (k * ratio).min(0.97)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn state_at(last_seen: Instant) -> ConversationState {
+            ConversationState { last_total_raw: 0, last_ratio: 0.0, last_seen }
+        }
+
+        #[test]
+        fn test_evict_stale_conversations_drops_entries_past_ttl() {
+            let mut conversations = HashMap::new();
+            conversations.insert(
+                "conv-expired".to_string(),
+                state_at(Instant::now() - CONVERSATION_TTL - Duration::from_secs(1)),
+            );
+            conversations.insert("conv-fresh".to_string(), state_at(Instant::now()));
+
+            evict_stale_conversations(&mut conversations);
+
+            assert!(!conversations.contains_key("conv-expired"));
+            assert!(conversations.contains_key("conv-fresh"));
+        }
+
+        #[test]
+        fn test_evict_stale_conversations_caps_table_size() {
+            let mut conversations = HashMap::new();
+            for i in 0..MAX_TRACKED_CONVERSATIONS {
+                conversations.insert(
+                    format!("conv-{i}"),
+                    state_at(Instant::now() - Duration::from_secs((MAX_TRACKED_CONVERSATIONS - i) as u64)),
+                );
+            }
+
+            evict_stale_conversations(&mut conversations);
+
+            assert!(conversations.len() < MAX_TRACKED_CONVERSATIONS);
+            assert!(!conversations.contains_key("conv-0"), "oldest entry should be the one evicted");
+        }
+
+        #[test]
+        fn test_conversation_table_stays_capped_through_public_api() {
+            let profile = global_registry().resolve("gemini-2.5-pro");
+
+            for i in 0..MAX_TRACKED_CONVERSATIONS + 10 {
+                display_ratio("gemini-2.5-pro-cap-test", &format!("conv-cap-{i}"), 100, &profile);
+            }
+
+            let conversations = conversation_table().lock().unwrap();
+            assert!(
+                conversations.len() <= MAX_TRACKED_CONVERSATIONS,
+                "conversation_table should never exceed its cap, even when driven through display_ratio"
+            );
+        }
     }
 }
 
-pub fn to_claude_usage(usage_metadata: &super::models::UsageMetadata, scaling_enabled: bool, context_limit: u32) -> super::models::Usage {
+pub fn to_claude_usage(
+    usage_metadata: &super::models::UsageMetadata,
+    profile: &ModelProfile,
+    model: &str,
+    conversation_id: &str,
+) -> super::models::Usage {
     let prompt_tokens = usage_metadata.prompt_token_count.unwrap_or(0);
     let cached_tokens = usage_metadata.cached_content_token_count.unwrap_or(0);
 
-    // 【改进的智能阈值回归算法】
-    // 目标：既利用 Gemini 大窗口，又能在高用量时让 Claude Code 正确触发 compact 提示
-    //
-    // 分阶段策略：
-    // - 0-50%:  激进压缩，享受大上下文
-    // - 50-70%: 开始加速回升
-    // - 70-85%: 快速回升到显示 70%+
-    // - 85%+:   接近 1:1 显示，确保触发 Claude Code 的 compact 提示
+    // 【自适应反馈控制算法】
+    // 不再用写死的分段曲线猜测,而是用一个按模型持久化、按会话观测 compact
+    // 骤降事件来自我调节的系数 k,使其收敛到 profile 里配置的真实填充率处
+    // 触发 Claude Code 的 compact 提示。
     let total_raw = prompt_tokens;
 
-    // [FIX] Restore low token threshold - don't scale if under 30k tokens
-    const SCALING_THRESHOLD: u32 = 30_000;
-
-    let scaled_total = if scaling_enabled && total_raw > SCALING_THRESHOLD {
-        const TARGET_MAX: f64 = 195_000.0; // 接近 Claude 的 200k 限制
-
-        let ratio = total_raw as f64 / context_limit as f64;
-
-        if ratio <= 0.5 {
-            // 阶段1 (0-50%): 激进压缩，享受大上下文
-            // 真实 50% → 显示 ~30%
-            let display_ratio = ratio * 0.6;
-            (display_ratio * TARGET_MAX) as u32
-        } else if ratio <= 0.7 {
-            // 阶段2 (50-70%): 开始加速回升
-            // 线性从 30% 回升到 50%
-            let progress = (ratio - 0.5) / 0.2;
-            let display_ratio = 0.3 + progress * 0.2;
-            (display_ratio * TARGET_MAX) as u32
-        } else if ratio <= 0.85 {
-            // 阶段3 (70-85%): 快速回升到显示 70%
-            // 这个阶段让用户开始注意到上下文在增长
-            let progress = (ratio - 0.7) / 0.15;
-            let display_ratio = 0.5 + progress * 0.2;
-            (display_ratio * TARGET_MAX) as u32
-        } else {
-            // 阶段4 (85%+): 接近 1:1 显示，触发 Claude Code 的 compact 提示
-            // 85% 真实 → 70% 显示
-            // 100% 真实 → 97% 显示
-            let progress = (ratio - 0.85) / 0.15;
-            let display_ratio = 0.7 + progress * 0.27;
-            (display_ratio.min(0.97) * TARGET_MAX) as u32
-        }
+    let scaled_total = if profile.scaling_enabled && total_raw > profile.scaling_threshold {
+        let ratio = total_raw as f64 / profile.context_limit.max(1) as f64;
+
+        // 优先用运营方配置的自定义缩放表达式;没配置或求值出错时回退到自适应曲线。
+        // 表达式是用户提供的任意算式,不像内置曲线那样天然落在 [0, 0.97] 里,
+        // 所以结果必须夹在这个区间——否则越过 0.97 会破坏 compact 触发的前提,
+        // 负数则会产生无意义的展示用量。
+        let display_ratio = scaling_expression::try_evaluate(&scaling_expression::ScalingInputs {
+            ratio,
+            total_raw: total_raw as f64,
+            cached_tokens: cached_tokens as f64,
+            context_limit: profile.context_limit as f64,
+            target_max: profile.target_max,
+        })
+        .map(|r| r.clamp(0.0, 0.97))
+        .unwrap_or_else(|| scaling_controller::display_ratio(model, conversation_id, total_raw, profile));
+
+        (display_ratio * profile.target_max) as u32
     } else {
         total_raw
     };
 
     // 【调试日志】方便手动验证
-    if scaling_enabled && total_raw > 30_000 {
-        let ratio = total_raw as f64 / context_limit as f64;
-        let display_ratio = scaled_total as f64 / 195_000.0;
+    if profile.scaling_enabled && total_raw > profile.scaling_threshold {
+        let ratio = total_raw as f64 / profile.context_limit as f64;
+        let display_ratio = scaled_total as f64 / profile.target_max;
         tracing::debug!(
             "[Claude-Scaling] Raw: {} ({:.1}%), Display: {} ({:.1}%), Compression: {:.1}x",
             total_raw, ratio * 100.0, scaled_total, display_ratio * 100.0,
             total_raw as f64 / scaled_total as f64
         );
     }
-    
+
     // 按比例分配缩放后的总量到 input 和 cache_read
     let (reported_input, reported_cache) = if total_raw > 0 {
         let cache_ratio = (cached_tokens as f64) / (total_raw as f64);
@@ -88,14 +285,19 @@ pub fn to_claude_usage(usage_metadata: &super::models::UsageMetadata, scaling_en
     } else {
         (scaled_total, None)
     };
-    
-    super::models::Usage {
+
+    let usage = super::models::Usage {
         input_tokens: reported_input,
         output_tokens: usage_metadata.candidates_token_count.unwrap_or(0),
         cache_read_input_tokens: reported_cache,
         cache_creation_input_tokens: Some(0),
         server_tool_use: None,
-    }
+    };
+
+    // 累加真实用量 vs 上报给 Claude Code 的展示用量,供 /admin/usage 端点查询
+    usage_tracker::record(conversation_id, model, profile.context_limit, usage_metadata, &usage);
+
+    usage
 }
 
 /// 提取 thoughtSignature
@@ -105,8 +307,12 @@ pub fn to_claude_usage(usage_metadata: &super::models::UsageMetadata, scaling_en
 mod tests {
     use super::*;
 
+    fn test_profile() -> ModelProfile {
+        global_registry().resolve("gemini-2.5-pro")
+    }
+
     #[test]
-    fn test_to_claude_usage() {
+    fn test_to_claude_usage_below_threshold_is_unscaled() {
         use super::super::models::UsageMetadata;
 
         let usage = UsageMetadata {
@@ -116,53 +322,109 @@ mod tests {
             cached_content_token_count: None,
         };
 
-        let claude_usage = to_claude_usage(&usage, true, 1_000_000);
         // 100 tokens is < 30k, minimal scaling
+        let claude_usage = to_claude_usage(&usage, &test_profile(), "gemini-pro", "conv-below-threshold");
         assert!(claude_usage.input_tokens < 200);
         assert_eq!(claude_usage.output_tokens, 50);
+    }
+
+    #[test]
+    fn test_to_claude_usage_applies_initial_k() {
+        use super::super::models::UsageMetadata;
 
-        // 测试 50% 负载 (500k) - 应该显示 ~30%
+        // 第一次看到这个模型时使用初始系数 K_INITIAL(0.6):
+        // 1,000,000 / 2,097,152 ≈ 47.7% 真实填充 * 0.6 ≈ 28.6% 显示, 即 195k 的 28.6% ≈ 55,800
         let usage_50 = UsageMetadata {
-            prompt_token_count: Some(500_000),
+            prompt_token_count: Some(1_000_000),
             candidates_token_count: Some(10),
-            total_token_count: Some(500_010),
+            total_token_count: Some(1_000_010),
             cached_content_token_count: None,
         };
-        let res_50 = to_claude_usage(&usage_50, true, 1_000_000);
-        // 50% * 0.6 = 30% of 195k = 58,500
+        let res_50 = to_claude_usage(&usage_50, &test_profile(), "gemini-2.5-pro-initial-k", "conv-a");
         assert!(res_50.input_tokens > 55_000 && res_50.input_tokens < 62_000);
+    }
+
+    #[test]
+    fn test_to_claude_usage_handles_compact_drop() {
+        use super::super::models::UsageMetadata;
+
+        let model = "gemini-2.5-pro-converge";
+        let conversation_id = "conv-b";
+        let profile = test_profile();
 
-        // 测试 70% 负载 (700k) - 应该显示 ~50%
-        let usage_70 = UsageMetadata {
-            prompt_token_count: Some(700_000),
+        // 第一条请求:真实填充约 76%(1,600,000 / 2,097,152),记录为下一条请求比较的基准
+        let usage_high = UsageMetadata {
+            prompt_token_count: Some(1_600_000),
             candidates_token_count: Some(10),
-            total_token_count: Some(700_010),
+            total_token_count: Some(1_600_010),
             cached_content_token_count: None,
         };
-        let res_70 = to_claude_usage(&usage_70, true, 1_000_000);
-        // 50% of 195k = 97,500
-        assert!(res_70.input_tokens > 90_000 && res_70.input_tokens < 105_000);
+        let res_high = to_claude_usage(&usage_high, &profile, model, conversation_id);
 
-        // 测试 85% 负载 (850k) - 应该显示 ~70%
-        let usage_85 = UsageMetadata {
-            prompt_token_count: Some(850_000),
+        // 第二条请求:prompt_token_count 骤降,说明 Claude Code 在上一条触发了 compact。
+        // 控制器据此应当调整该模型的自适应系数 k。
+        let usage_after_compact = UsageMetadata {
+            prompt_token_count: Some(40_000),
             candidates_token_count: Some(10),
-            total_token_count: Some(850_010),
+            total_token_count: Some(40_010),
             cached_content_token_count: None,
         };
-        let res_85 = to_claude_usage(&usage_85, true, 1_000_000);
-        // 70% of 195k = 136,500
-        assert!(res_85.input_tokens > 130_000 && res_85.input_tokens < 145_000);
+        let res_after_compact = to_claude_usage(&usage_after_compact, &profile, model, conversation_id);
 
-        // 测试 100% 负载 (1M) - 应该显示 ~97%
-        let usage_100 = UsageMetadata {
-            prompt_token_count: Some(1_000_000),
+        // 触发骤降事件本身不应 panic,且仍返回合理的展示用量
+        assert!(res_high.input_tokens > 0);
+        assert!(res_after_compact.input_tokens > 0);
+    }
+
+    #[test]
+    fn test_custom_registry_profile_changes_output() {
+        use super::super::models::UsageMetadata;
+
+        let raw = r#"
+            default_profile = { context_limit = 1000000, target_max = 195000.0, scaling_threshold = 30000, compact_threshold = 0.92, target_true_fill = 0.90, scaling_enabled = true }
+
+            [[rules]]
+            model_glob = "no-scaling-*"
+            context_limit = 1000000
+            target_max = 195000.0
+            scaling_threshold = 30000
+            compact_threshold = 0.92
+            target_true_fill = 0.90
+            scaling_enabled = false
+        "#;
+        let registry = ModelRegistry::from_toml_str(raw).expect("valid toml");
+        let profile = registry.resolve("no-scaling-model");
+
+        let usage = UsageMetadata {
+            prompt_token_count: Some(900_000),
             candidates_token_count: Some(10),
-            total_token_count: Some(1_000_010),
+            total_token_count: Some(900_010),
             cached_content_token_count: None,
         };
-        let res_100 = to_claude_usage(&usage_100, true, 1_000_000);
-        // 97% of 195k = 189,150
-        assert!(res_100.input_tokens > 185_000 && res_100.input_tokens <= 190_000);
+        // scaling_enabled = false 的自定义 profile 应该原样上报,不走缩放曲线
+        let result = to_claude_usage(&usage, &profile, "no-scaling-model", "conv-custom");
+        assert_eq!(result.input_tokens, 900_000);
+    }
+
+    #[test]
+    fn test_custom_expression_output_is_clamped_before_scaling() {
+        use super::super::models::UsageMetadata;
+
+        // 故意返回一个越界的 display ratio(2.0,远超过 0.97 的上限)
+        scaling_expression::configure("2.0").expect("valid expression");
+
+        let profile = test_profile();
+        let usage = UsageMetadata {
+            prompt_token_count: Some(900_000),
+            candidates_token_count: Some(10),
+            total_token_count: Some(900_010),
+            cached_content_token_count: None,
+        };
+        let result = to_claude_usage(&usage, &profile, "gemini-clamp-test", "conv-clamp");
+
+        // 夹到 0.97 之后乘以 target_max,不应该出现 2 倍 target_max 的离谱结果
+        assert!(result.input_tokens <= (profile.target_max * 0.97) as u32 + 1);
+
+        scaling_expression::clear();
     }
 }
@@ -0,0 +1,192 @@
+// 数据驱动的模型注册表
+//
+// `get_context_limit_for_model` 过去用 `contains("pro")` / `contains("flash")`
+// 这种脆弱的子串匹配,并且所有模型共用同一套缩放曲线参数。这里把模型名 glob
+// 到缩放 profile 的映射外置成配置(TOML/JSON),`to_claude_usage` 只认解析后的
+// `ModelProfile`,不再直接接收裸的 `context_limit: u32`。
+
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+/// 单个模型的缩放 profile:上下文窗口大小,以及自适应控制器相关的参数。
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelProfile {
+    /// 模型的真实上下文窗口大小
+    pub context_limit: u32,
+    /// 报给 Claude Code 的展示上限,对应原先的 TARGET_MAX
+    pub target_max: f64,
+    /// 低于该真实 token 数不做任何缩放
+    pub scaling_threshold: u32,
+    /// Claude Code 触发 compact 提示时的展示填充率阈值
+    pub compact_threshold: f64,
+    /// 控制器想要收敛到的真实填充率
+    pub target_true_fill: f64,
+    /// 整体是否启用缩放;关闭时按真实 token 数原样上报
+    #[serde(default = "default_true")]
+    pub scaling_enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl ModelProfile {
+    fn pro_default() -> Self {
+        Self {
+            context_limit: 2_097_152,
+            target_max: 195_000.0,
+            scaling_threshold: 30_000,
+            compact_threshold: 0.92,
+            target_true_fill: 0.90,
+            scaling_enabled: true,
+        }
+    }
+
+    fn flash_default() -> Self {
+        Self {
+            context_limit: 1_048_576,
+            target_max: 195_000.0,
+            scaling_threshold: 30_000,
+            compact_threshold: 0.92,
+            target_true_fill: 0.90,
+            scaling_enabled: true,
+        }
+    }
+}
+
+/// 一条 `模型名 glob -> profile` 的配置项
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelProfileRule {
+    /// 支持 `*` 通配符的模型名匹配模式,例如 `"*pro*"` / `"gemini-2.5-flash*"`
+    pub model_glob: String,
+    #[serde(flatten)]
+    pub profile: ModelProfile,
+}
+
+/// 从配置加载的模型注册表,按规则顺序匹配,命中第一条即返回。
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelRegistry {
+    #[serde(default)]
+    pub rules: Vec<ModelProfileRule>,
+    #[serde(default = "ModelProfile::flash_default")]
+    pub default_profile: ModelProfile,
+}
+
+impl ModelRegistry {
+    /// 内置默认值:与重构前硬编码的 2M/1M 限制保持一致,
+    /// 不提供配置文件时行为不变。
+    pub fn builtin_defaults() -> Self {
+        Self {
+            rules: vec![
+                ModelProfileRule {
+                    model_glob: "*pro*".to_string(),
+                    profile: ModelProfile::pro_default(),
+                },
+                ModelProfileRule {
+                    model_glob: "*flash*".to_string(),
+                    profile: ModelProfile::flash_default(),
+                },
+            ],
+            default_profile: ModelProfile::flash_default(),
+        }
+    }
+
+    pub fn from_toml_str(raw: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(raw)
+    }
+
+    pub fn from_json_str(raw: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(raw)
+    }
+
+    /// 按规则顺序解析某个模型名应使用的 profile,没有规则命中时回退到 `default_profile`。
+    pub fn resolve(&self, model: &str) -> ModelProfile {
+        for rule in &self.rules {
+            if glob_match(&rule.model_glob, model) {
+                return rule.profile.clone();
+            }
+        }
+        self.default_profile.clone()
+    }
+}
+
+/// 全局默认注册表,operator 没有提供自定义配置时使用。
+pub fn global_registry() -> &'static ModelRegistry {
+    static REGISTRY: OnceLock<ModelRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(ModelRegistry::builtin_defaults)
+}
+
+/// 极简的 `*` 通配符匹配,够用即可,不需要引入完整的 glob crate。
+fn glob_match(pattern: &str, candidate: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == candidate;
+    }
+
+    let mut remaining = candidate;
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() {
+            continue;
+        }
+        if i == 0 {
+            if !remaining.starts_with(part) {
+                return false;
+            }
+            remaining = &remaining[part.len()..];
+        } else if i == parts.len() - 1 {
+            return remaining.ends_with(part);
+        } else if let Some(pos) = remaining.find(part) {
+            remaining = &remaining[pos + part.len()..];
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_defaults_match_legacy_limits() {
+        let registry = ModelRegistry::builtin_defaults();
+        assert_eq!(registry.resolve("gemini-2.5-pro").context_limit, 2_097_152);
+        assert_eq!(registry.resolve("gemini-2.5-flash").context_limit, 1_048_576);
+        assert_eq!(registry.resolve("unknown-model").context_limit, 1_048_576);
+    }
+
+    #[test]
+    fn test_custom_profile_overrides_defaults() {
+        let raw = r#"
+            default_profile = { context_limit = 500000, target_max = 100000.0, scaling_threshold = 10000, compact_threshold = 0.9, target_true_fill = 0.85, scaling_enabled = true }
+
+            [[rules]]
+            model_glob = "custom-model-*"
+            context_limit = 42
+            target_max = 1000.0
+            scaling_threshold = 1
+            compact_threshold = 0.5
+            target_true_fill = 0.4
+            scaling_enabled = false
+        "#;
+
+        let registry = ModelRegistry::from_toml_str(raw).expect("valid toml");
+        let resolved = registry.resolve("custom-model-x");
+        assert_eq!(resolved.context_limit, 42);
+        assert!(!resolved.scaling_enabled);
+
+        // 没有匹配任何规则的模型名应落到自定义的 default_profile
+        let fallback = registry.resolve("totally-different-model");
+        assert_eq!(fallback.context_limit, 500_000);
+    }
+
+    #[test]
+    fn test_glob_match_wildcard_positions() {
+        assert!(glob_match("*pro*", "gemini-2.5-pro-preview"));
+        assert!(glob_match("gemini-2.5-flash*", "gemini-2.5-flash-001"));
+        assert!(!glob_match("gemini-2.5-flash*", "gemini-2.5-pro-001"));
+        assert!(glob_match("exact-name", "exact-name"));
+        assert!(!glob_match("exact-name", "exact-name-2"));
+    }
+}
@@ -0,0 +1,129 @@
+// Gemini 转发路径:把 Claude Code 发来的这一轮对话历史,转换成实际发给
+// Gemini `generateContent` 的轮次列表。RAG 压缩子系统的入口就挂在这里——
+// 在历史被序列化进请求体之前,先看看要不要先做检索增强裁剪。
+
+use super::super::super::super::compaction::{self, ConversationTurn};
+
+/// 构建本次转发给 Gemini 的对话轮次。
+///
+/// 当真实 `prompt_token_count` 越过 RAG 子系统配置的触发比例时,
+/// 返回的是检索增强后的裁剪历史;否则返回完整历史,和压缩子系统
+/// 接入前的行为一致。
+pub async fn build_gemini_turns(
+    conversation_id: &str,
+    model: &str,
+    system: Option<&ConversationTurn>,
+    history: &[ConversationTurn],
+    latest_user_message: &str,
+    prompt_token_count: u32,
+) -> Vec<ConversationTurn> {
+    let context_limit = super::global_registry().resolve(model).context_limit;
+
+    compaction::compact_before_forwarding(
+        conversation_id,
+        prompt_token_count,
+        context_limit,
+        system,
+        history,
+        latest_user_message,
+    )
+    .await
+}
+
+/// 真正的调用点:把 Claude Code 这一轮请求里的 `(role, text)` 消息列表
+/// (由上游消息解析逻辑从 Claude 的 `messages` 字段展开而来,与计算
+/// `prompt_token_count` 所用的是同一份数据)接入 RAG 压缩,再序列化成
+/// Gemini `generateContent` 请求体里的 `contents` / `systemInstruction`。
+pub async fn build_gemini_request_body(
+    conversation_id: &str,
+    model: &str,
+    system_prompt: Option<&str>,
+    messages: &[(String, String)],
+    prompt_token_count: u32,
+) -> serde_json::Value {
+    let system_turn = system_prompt.map(|text| ConversationTurn {
+        role: "system".to_string(),
+        text: text.to_string(),
+    });
+    let history: Vec<ConversationTurn> = messages
+        .iter()
+        .map(|(role, text)| ConversationTurn { role: role.clone(), text: text.clone() })
+        .collect();
+    let latest_user_message = messages
+        .iter()
+        .rev()
+        .find(|(role, _)| role == "user")
+        .map(|(_, text)| text.as_str())
+        .unwrap_or("");
+
+    let turns = build_gemini_turns(
+        conversation_id,
+        model,
+        system_turn.as_ref(),
+        &history,
+        latest_user_message,
+        prompt_token_count,
+    )
+    .await;
+
+    let contents: Vec<serde_json::Value> = turns
+        .iter()
+        .filter(|turn| turn.role != "system")
+        .map(|turn| {
+            let gemini_role = if turn.role == "assistant" { "model" } else { "user" };
+            serde_json::json!({ "role": gemini_role, "parts": [{ "text": turn.text }] })
+        })
+        .collect();
+
+    let mut body = serde_json::json!({ "contents": contents });
+    if let Some(system_turn) = turns.iter().find(|turn| turn.role == "system") {
+        body["systemInstruction"] = serde_json::json!({ "parts": [{ "text": system_turn.text }] });
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_build_gemini_request_body_passes_through_without_rag_runtime() {
+        let body = build_gemini_request_body(
+            "conv-request-builder-test",
+            "gemini-2.5-flash",
+            Some("be concise"),
+            &[
+                ("user".to_string(), "hello".to_string()),
+                ("assistant".to_string(), "hi there".to_string()),
+            ],
+            100,
+        )
+        .await;
+
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], "be concise");
+        assert_eq!(body["contents"][0]["role"], "user");
+        assert_eq!(body["contents"][0]["parts"][0]["text"], "hello");
+        assert_eq!(body["contents"][1]["role"], "model");
+    }
+
+    #[tokio::test]
+    async fn test_build_gemini_request_body_keeps_trailing_assistant_turn() {
+        // 最后一条消息是 assistant 的回复;未配置 RAG runtime 时两轮都按原顺序透传。
+        // 这里不检查 latest_user_message 的取值,它只在 RAG 检索时用到。
+        let body = build_gemini_request_body(
+            "conv-request-builder-latest-message-test",
+            "gemini-2.5-flash",
+            None,
+            &[
+                ("user".to_string(), "what's the weather".to_string()),
+                ("assistant".to_string(), "sunny".to_string()),
+            ],
+            100,
+        )
+        .await;
+
+        assert_eq!(body["contents"][0]["parts"][0]["text"], "what's the weather");
+        assert_eq!(body["contents"][1]["parts"][0]["text"], "sunny");
+    }
+}
@@ -0,0 +1,167 @@
+// 可配置的缩放表达式
+//
+// 压缩曲线原本是编译进二进制的算术,这里允许运营方提供一段 Rhai 表达式,
+// 在 `to_claude_usage` 里替代写死的分段/自适应曲线。表达式在启动时只解析
+// 校验一次,之后每次请求只是求值,出错或未配置时回退到内置曲线。
+
+use rhai::{Engine, Scope, AST};
+use std::sync::OnceLock;
+use std::sync::RwLock;
+
+/// 表达式可以引用的输入变量
+pub struct ScalingInputs {
+    pub ratio: f64,
+    pub total_raw: f64,
+    pub cached_tokens: f64,
+    pub context_limit: f64,
+    pub target_max: f64,
+}
+
+#[derive(Debug)]
+pub enum ScalingExpressionError {
+    Parse(String),
+    Eval(String),
+}
+
+impl std::fmt::Display for ScalingExpressionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScalingExpressionError::Parse(msg) => write!(f, "failed to parse scaling expression: {msg}"),
+            ScalingExpressionError::Eval(msg) => write!(f, "failed to evaluate scaling expression: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ScalingExpressionError {}
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(Engine::new)
+}
+
+fn configured_ast() -> &'static RwLock<Option<AST>> {
+    static AST_SLOT: OnceLock<RwLock<Option<AST>>> = OnceLock::new();
+    AST_SLOT.get_or_init(|| RwLock::new(None))
+}
+
+/// 解析并校验运营方提供的缩放表达式,成功后替换全局配置。
+/// 只在启动时调用一次,避免每次请求都重新解析脚本。
+pub fn configure(expression: &str) -> Result<(), ScalingExpressionError> {
+    let ast = engine()
+        .compile(expression)
+        .map_err(|e| ScalingExpressionError::Parse(e.to_string()))?;
+
+    // 用占位输入跑一遍,提前暴露类型错误之类的问题,而不是留到第一次真实请求才炸
+    let mut scope = Scope::new();
+    bind_inputs(
+        &mut scope,
+        &ScalingInputs {
+            ratio: 0.5,
+            total_raw: 500_000.0,
+            cached_tokens: 0.0,
+            context_limit: 1_000_000.0,
+            target_max: 195_000.0,
+        },
+    );
+    engine()
+        .eval_ast_with_scope::<f64>(&mut scope, &ast)
+        .map_err(|e| ScalingExpressionError::Eval(e.to_string()))?;
+
+    *configured_ast().write().unwrap() = Some(ast);
+    Ok(())
+}
+
+/// 清除已配置的表达式,恢复成"未配置",后续请求会回退到内置曲线。
+pub fn clear() {
+    *configured_ast().write().unwrap() = None;
+}
+
+fn bind_inputs(scope: &mut Scope, inputs: &ScalingInputs) {
+    scope.push("ratio", inputs.ratio);
+    scope.push("total_raw", inputs.total_raw);
+    scope.push("cached_tokens", inputs.cached_tokens);
+    scope.push("context_limit", inputs.context_limit);
+    scope.push("target_max", inputs.target_max);
+}
+
+/// 如果配置了表达式,用它算出 display ratio;否则返回 `None` 让调用方回退到内置曲线。
+/// 表达式求值出错时同样返回 `None`,并打一条 warn 日志,而不是让请求失败。
+pub fn try_evaluate(inputs: &ScalingInputs) -> Option<f64> {
+    let guard = configured_ast().read().unwrap();
+    let ast = guard.as_ref()?;
+
+    let mut scope = Scope::new();
+    bind_inputs(&mut scope, inputs);
+
+    match engine().eval_ast_with_scope::<f64>(&mut scope, ast) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            tracing::warn!("[Claude-Scaling] custom scaling expression failed, falling back to built-in curve: {err}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configure_and_evaluate_simple_expression() {
+        configure("ratio * 0.5").expect("valid expression");
+
+        let result = try_evaluate(&ScalingInputs {
+            ratio: 0.8,
+            total_raw: 800_000.0,
+            cached_tokens: 0.0,
+            context_limit: 1_000_000.0,
+            target_max: 195_000.0,
+        });
+
+        assert_eq!(result, Some(0.4));
+        clear();
+    }
+
+    #[test]
+    fn test_unconfigured_expression_returns_none() {
+        clear();
+        let result = try_evaluate(&ScalingInputs {
+            ratio: 0.5,
+            total_raw: 500_000.0,
+            cached_tokens: 0.0,
+            context_limit: 1_000_000.0,
+            target_max: 195_000.0,
+        });
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn test_invalid_expression_is_rejected_at_configure_time() {
+        let result = configure("this is not valid rhai (((");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_expression_referencing_unknown_variable_is_rejected_at_configure_time() {
+        // `foo` 不在 bind_inputs 绑定的变量里,应该在 configure() 的占位试跑阶段
+        // 就被拒绝,而不是留到第一次真实请求才在 try_evaluate 里炸出来。
+        let result = configure("foo * 0.5");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_all_bound_inputs_are_usable_in_expression() {
+        configure("ratio + total_raw + cached_tokens + context_limit + target_max").expect("valid expression");
+
+        let result = try_evaluate(&ScalingInputs {
+            ratio: 1.0,
+            total_raw: 2.0,
+            cached_tokens: 3.0,
+            context_limit: 4.0,
+            target_max: 5.0,
+        });
+
+        assert_eq!(result, Some(15.0));
+        clear();
+    }
+}
@@ -0,0 +1,312 @@
+// 按会话累计真实用量 vs. 上报给 Claude Code 的展示用量。
+//
+// `to_claude_usage` 本身是无状态的,算完一次就忘了,没法回答"这个会话
+// 趋势如何""还剩多少配额"这类问题。这里维护一个全局的会话用量表,
+// 并提供只读的管理端点数据源,供 `/admin/usage` 之类的路由渲染。
+//
+// 路由挂载位置: 管理端 HTTP router(例如 `proxy/admin.rs`)里
+// `GET /admin/usage` -> `usage_tracker::usage_report()`
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// 会话用量表没有自然的"会话结束"信号(代理看不到前端什么时候关闭了窗口),
+/// 长期运行下去这张表只会无限增长。这里用一个简单的 TTL + 容量上限做淘汰:
+/// 超过 TTL 没再记录过用量的会话先被清走;如果清完还是超过容量上限,
+/// 再按最后一次记录时间淘汰最旧的那一个。
+const SESSION_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+const MAX_TRACKED_SESSIONS: usize = 5_000;
+
+/// 单个会话累计到目前为止的真实用量与上报给 Claude Code 的展示用量
+#[derive(Debug, Clone, Default)]
+pub struct SessionUsage {
+    pub model: String,
+    pub context_limit: u32,
+
+    /// 跨请求累计的真实 token 数,用于统计该会话总共花了 Gemini 多少 token
+    pub real_prompt_tokens: u64,
+    pub real_candidates_tokens: u64,
+    pub real_cached_tokens: u64,
+
+    /// 最近一次请求里真实的 prompt token 数。Gemini 的
+    /// `prompt_token_count` 本身就是这一轮完整对话的真实大小(不是增量,
+    /// 且已包含 cache 部分),所以算"距上下文上限还剩多少"要用这个值,
+    /// 而不是跨请求的累计和。
+    last_prompt_tokens: u64,
+
+    pub displayed_input_tokens: u64,
+    pub displayed_output_tokens: u64,
+    pub displayed_cache_tokens: u64,
+}
+
+fn sessions() -> &'static Mutex<HashMap<String, (Instant, SessionUsage)>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<String, (Instant, SessionUsage)>>> = OnceLock::new();
+    SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// 先按 TTL 清掉太久没动静的会话;如果清完还是超过容量上限,
+/// 再淘汰最后一次记录时间最早的那一个。
+fn evict_stale_sessions(table: &mut HashMap<String, (Instant, SessionUsage)>) {
+    let now = Instant::now();
+    table.retain(|_, (last_seen, _)| now.duration_since(*last_seen) < SESSION_TTL);
+
+    if table.len() >= MAX_TRACKED_SESSIONS {
+        if let Some(oldest_id) = table
+            .iter()
+            .min_by_key(|(_, (last_seen, _))| *last_seen)
+            .map(|(conversation_id, _)| conversation_id.clone())
+        {
+            table.remove(&oldest_id);
+        }
+    }
+}
+
+/// 累加一次请求的真实用量和实际上报给 Claude Code 的展示用量。
+pub fn record(
+    conversation_id: &str,
+    model: &str,
+    context_limit: u32,
+    usage_metadata: &super::super::models::UsageMetadata,
+    reported: &super::super::models::Usage,
+) {
+    let mut table = sessions().lock().unwrap();
+    evict_stale_sessions(&mut table);
+
+    let (last_seen, entry) = table
+        .entry(conversation_id.to_string())
+        .or_insert_with(|| (Instant::now(), SessionUsage::default()));
+    *last_seen = Instant::now();
+
+    entry.model = model.to_string();
+    entry.context_limit = context_limit;
+    entry.real_prompt_tokens += usage_metadata.prompt_token_count.unwrap_or(0) as u64;
+    entry.real_candidates_tokens += usage_metadata.candidates_token_count.unwrap_or(0) as u64;
+    entry.real_cached_tokens += usage_metadata.cached_content_token_count.unwrap_or(0) as u64;
+
+    entry.last_prompt_tokens = usage_metadata.prompt_token_count.unwrap_or(0) as u64;
+
+    entry.displayed_input_tokens += reported.input_tokens as u64;
+    entry.displayed_output_tokens += reported.output_tokens as u64;
+    entry.displayed_cache_tokens += reported.cache_read_input_tokens.unwrap_or(0) as u64;
+}
+
+/// 管理端点返回的一行数据:某个会话的真实 vs 展示用量,以及距上下文上限还剩多少。
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SessionUsageReport {
+    pub conversation_id: String,
+    pub model: String,
+
+    pub real_input_tokens: u64,
+    pub real_output_tokens: u64,
+    pub real_cache_tokens: u64,
+    pub real_input_tokens_human: String,
+
+    pub displayed_input_tokens: u64,
+    pub displayed_output_tokens: u64,
+    pub displayed_cache_tokens: u64,
+    pub displayed_input_tokens_human: String,
+
+    pub context_limit: u32,
+    pub context_limit_human: String,
+    pub remaining_headroom_tokens: u64,
+    pub remaining_headroom_human: String,
+}
+
+/// Token 数量不是字节数,`humansize::BINARY`(KiB/MiB/GiB)会让人误以为这是
+/// 内存/磁盘占用。这里按千位分组输出一个 "1,000,000 tok" 这样的纯数字展示。
+fn humanize(tokens: u64) -> String {
+    let digits = tokens.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+
+    for (i, ch) in digits.chars().rev().enumerate() {
+        if i > 0 && i % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(ch);
+    }
+
+    format!("{} tok", grouped.chars().rev().collect::<String>())
+}
+
+/// 生成所有活跃会话的用量快照,供只读管理端点使用。
+pub fn usage_report() -> Vec<SessionUsageReport> {
+    let table = sessions().lock().unwrap();
+    table
+        .iter()
+        .map(|(conversation_id, (_, usage))| {
+            // 用最近一次请求的真实填充量算剩余配额,而不是跨请求累计和——
+            // `prompt_token_count` 每次都是这一轮的完整真实大小,不是增量。
+            let last_total = usage.last_prompt_tokens;
+            let remaining = (usage.context_limit as u64).saturating_sub(last_total);
+            SessionUsageReport {
+                conversation_id: conversation_id.clone(),
+                model: usage.model.clone(),
+
+                real_input_tokens: usage.real_prompt_tokens,
+                real_output_tokens: usage.real_candidates_tokens,
+                real_cache_tokens: usage.real_cached_tokens,
+                real_input_tokens_human: humanize(usage.real_prompt_tokens),
+
+                displayed_input_tokens: usage.displayed_input_tokens,
+                displayed_output_tokens: usage.displayed_output_tokens,
+                displayed_cache_tokens: usage.displayed_cache_tokens,
+                displayed_input_tokens_human: humanize(usage.displayed_input_tokens),
+
+                context_limit: usage.context_limit,
+                context_limit_human: humanize(usage.context_limit as u64),
+                remaining_headroom_tokens: remaining,
+                remaining_headroom_human: humanize(remaining),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::models::{Usage, UsageMetadata};
+
+    #[test]
+    fn test_humanize_formats_tokens_not_bytes() {
+        assert_eq!(humanize(0), "0 tok");
+        assert_eq!(humanize(500), "500 tok");
+        assert_eq!(humanize(1_000_000), "1,000,000 tok");
+    }
+
+    #[test]
+    fn test_usage_report_human_fields_never_use_byte_units() {
+        let conversation_id = "conv-human-units-test";
+        let usage_metadata = UsageMetadata {
+            prompt_token_count: Some(2_000_000),
+            candidates_token_count: Some(1_000),
+            total_token_count: Some(2_001_000),
+            cached_content_token_count: Some(0),
+        };
+        let reported = Usage {
+            input_tokens: 2_000_000,
+            output_tokens: 1_000,
+            cache_read_input_tokens: Some(0),
+            cache_creation_input_tokens: Some(0),
+            server_tool_use: None,
+        };
+
+        record(conversation_id, "gemini-2.5-pro", 2_097_152, &usage_metadata, &reported);
+
+        let report = usage_report()
+            .into_iter()
+            .find(|r| r.conversation_id == conversation_id)
+            .expect("session should be tracked");
+
+        // 2,000,000 tokens 如果走 humansize::BINARY 会被渲染成 "1.91 MiB" 这样的
+        // 字节单位;确认整份报表里的人类可读字段都不会出现这类单位后缀。
+        for human in [
+            &report.real_input_tokens_human,
+            &report.displayed_input_tokens_human,
+            &report.context_limit_human,
+            &report.remaining_headroom_human,
+        ] {
+            assert!(human.ends_with("tok"), "expected a token-count suffix, got: {human}");
+            for unit in ["KiB", "MiB", "GiB", "KB", "MB", "GB"] {
+                assert!(!human.contains(unit), "human field leaked a byte unit: {human}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_accumulates_across_requests() {
+        let conversation_id = "conv-usage-test";
+        let usage_metadata = UsageMetadata {
+            prompt_token_count: Some(1_000),
+            candidates_token_count: Some(100),
+            total_token_count: Some(1_100),
+            cached_content_token_count: Some(50),
+        };
+        let reported = Usage {
+            input_tokens: 900,
+            output_tokens: 100,
+            cache_read_input_tokens: Some(40),
+            cache_creation_input_tokens: Some(0),
+            server_tool_use: None,
+        };
+
+        record(conversation_id, "gemini-2.5-pro", 2_097_152, &usage_metadata, &reported);
+        record(conversation_id, "gemini-2.5-pro", 2_097_152, &usage_metadata, &reported);
+
+        let report = usage_report()
+            .into_iter()
+            .find(|r| r.conversation_id == conversation_id)
+            .expect("session should be tracked");
+
+        assert_eq!(report.real_input_tokens, 2_000);
+        assert_eq!(report.displayed_input_tokens, 1_800);
+        assert!(report.remaining_headroom_tokens > 0);
+    }
+
+    #[test]
+    fn test_headroom_uses_latest_request_not_cumulative_sum() {
+        let conversation_id = "conv-headroom-test";
+        let context_limit = 1_000;
+
+        // 每次请求的真实 prompt_token_count 都是 600(这一轮完整大小,不是增量),
+        // 累计跨请求求和会超过 context_limit,但"剩余配额"应该只看最近一次。
+        let usage_metadata = UsageMetadata {
+            prompt_token_count: Some(600),
+            candidates_token_count: Some(10),
+            total_token_count: Some(610),
+            cached_content_token_count: None,
+        };
+        let reported = Usage {
+            input_tokens: 600,
+            output_tokens: 10,
+            cache_read_input_tokens: None,
+            cache_creation_input_tokens: Some(0),
+            server_tool_use: None,
+        };
+
+        record(conversation_id, "gemini-2.5-pro", context_limit, &usage_metadata, &reported);
+        record(conversation_id, "gemini-2.5-pro", context_limit, &usage_metadata, &reported);
+        record(conversation_id, "gemini-2.5-pro", context_limit, &usage_metadata, &reported);
+
+        let report = usage_report()
+            .into_iter()
+            .find(|r| r.conversation_id == conversation_id)
+            .expect("session should be tracked");
+
+        // 累计真实 token (1800) 已经超过 context_limit (1000)
+        assert!(report.real_input_tokens > context_limit as u64);
+        // 但剩余配额应该按最近一次的 600 算,而不是永久饱和为 0
+        assert_eq!(report.remaining_headroom_tokens, 400);
+    }
+
+    #[test]
+    fn test_evict_stale_sessions_drops_entries_past_ttl() {
+        let mut table = HashMap::new();
+        table.insert(
+            "conv-expired".to_string(),
+            (Instant::now() - SESSION_TTL - Duration::from_secs(1), SessionUsage::default()),
+        );
+        table.insert("conv-fresh".to_string(), (Instant::now(), SessionUsage::default()));
+
+        evict_stale_sessions(&mut table);
+
+        assert!(!table.contains_key("conv-expired"));
+        assert!(table.contains_key("conv-fresh"));
+    }
+
+    #[test]
+    fn test_evict_stale_sessions_caps_table_size() {
+        let mut table = HashMap::new();
+        for i in 0..MAX_TRACKED_SESSIONS {
+            table.insert(
+                format!("conv-{i}"),
+                (Instant::now() - Duration::from_secs((MAX_TRACKED_SESSIONS - i) as u64), SessionUsage::default()),
+            );
+        }
+
+        evict_stale_sessions(&mut table);
+
+        assert!(table.len() < MAX_TRACKED_SESSIONS);
+        assert!(!table.contains_key("conv-0"), "oldest entry should be the one evicted");
+    }
+}
@@ -0,0 +1,3 @@
+pub mod admin;
+pub mod compaction;
+pub mod mappers;